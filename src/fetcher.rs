@@ -0,0 +1,20 @@
+use crate::registry::config::DockerConfig;
+use crate::sources::Sources;
+use anyhow::Result;
+use async_trait::async_trait;
+use std::path::Path;
+use url::Url;
+
+/// Implemented by the per-scheme fetchers (`file`, `http`/`https`,
+/// `registry`) that `url_fetcher` dispatches to. `fetch` downloads the
+/// policy at `url` and writes it to `destination`.
+#[async_trait]
+pub trait Fetcher {
+    async fn fetch(
+        &self,
+        url: &Url,
+        destination: &Path,
+        sources: Option<&Sources>,
+        docker_config: Option<&DockerConfig>,
+    ) -> Result<()>;
+}
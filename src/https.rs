@@ -0,0 +1,291 @@
+use crate::fetcher::Fetcher;
+use crate::host_and_port;
+use crate::random_hex;
+use crate::registry::config::{mint_paseto_token, DockerConfig, RegistryAuth};
+use crate::sources::Sources;
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use reqwest::header::{HeaderValue, AUTHORIZATION, WWW_AUTHENTICATE};
+use reqwest::StatusCode;
+use std::collections::HashMap;
+use std::path::Path;
+use url::{Position, Url};
+
+pub(crate) struct Https {}
+
+#[async_trait]
+impl Fetcher for Https {
+    async fn fetch(
+        &self,
+        url: &Url,
+        destination: &Path,
+        sources: Option<&Sources>,
+        docker_config: Option<&DockerConfig>,
+    ) -> Result<()> {
+        let client = build_client(url, sources)?;
+
+        let mut request = client.get(url.as_str());
+        if let Some(bearer) = bearer_for_host(url, sources)? {
+            request = request.header(AUTHORIZATION, HeaderValue::from_str(&bearer)?);
+        }
+        let response = request.send().await?;
+
+        let response = if response.status() == StatusCode::UNAUTHORIZED {
+            let challenge = response
+                .headers()
+                .get(WWW_AUTHENTICATE)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string)
+                .filter(|value| {
+                    let value = value.to_ascii_lowercase();
+                    value.starts_with("digest ") || value.starts_with("basic ")
+                })
+                .ok_or_else(|| anyhow!("cannot fetch policy from {}: {}", url, response.status()))?;
+
+            let (username, password) = credentials_for_host(url, sources, docker_config)
+                .ok_or_else(|| {
+                    anyhow!(
+                        "{} requires authentication, but no credentials are configured for this host",
+                        url
+                    )
+                })?;
+
+            let authorization = if challenge.to_ascii_lowercase().starts_with("basic ") {
+                format!("Basic {}", base64::encode(format!("{}:{}", username, password)))
+            } else {
+                // Must match the exact request-target (path + query), not just
+                // the path, since `client.get` is sent with the full URL.
+                let request_target = &url[Position::BeforePath..Position::AfterQuery];
+                DigestChallenge::parse(&challenge)?.authorization(
+                    &username,
+                    &password,
+                    "GET",
+                    request_target,
+                )
+            };
+            client
+                .get(url.as_str())
+                .header(AUTHORIZATION, HeaderValue::from_str(&authorization)?)
+                .send()
+                .await?
+        } else {
+            response
+        };
+
+        if !response.status().is_success() {
+            return Err(anyhow!(
+                "cannot fetch policy from {}: {}",
+                url,
+                response.status()
+            ));
+        }
+
+        let body = response.bytes().await?;
+        if let Some(parent) = destination.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(destination, &body)?;
+
+        Ok(())
+    }
+}
+
+// Builds the client used for the GET (and, on a digest challenge, the
+// authenticated retry). Honors the on-disk CA bundle and/or
+// `insecure_allowed` a source rule configured for this host, if any.
+//
+// NOTE: only this (Https) fetcher consults `Sources` rules for CA
+// pinning/insecure TLS; the `registry://` fetcher does not yet.
+fn build_client(url: &Url, sources: Option<&Sources>) -> Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder();
+
+    let rule = host_and_port(url)
+        .ok()
+        .and_then(|host| sources.and_then(|sources| sources.rule_for_host(&host)));
+    if let Some(rule) = rule {
+        if let Some(ca_file) = &rule.ca_file {
+            let pem = std::fs::read(ca_file)?;
+            builder = builder.add_root_certificate(reqwest::Certificate::from_pem(&pem)?);
+        }
+        if rule.insecure_allowed {
+            builder = builder.danger_accept_invalid_certs(true);
+        }
+    }
+
+    Ok(builder.build()?)
+}
+
+// A source rule's `auth` can be a `Token`/`AsymmetricKey` rather than a
+// `BasicAuth`, in which case it's sent preemptively as a bearer credential
+// rather than through the (Basic/Digest-only) `WWW-Authenticate` flow.
+fn bearer_for_host(url: &Url, sources: Option<&Sources>) -> Result<Option<String>> {
+    let host = host_and_port(url)?;
+    let rule_auth = sources
+        .and_then(|sources| sources.rule_for_host(&host))
+        .and_then(|rule| rule.auth.as_ref());
+
+    match rule_auth {
+        Some(RegistryAuth::Token(token)) => Ok(Some(format!("Bearer {}", token))),
+        Some(RegistryAuth::AsymmetricKey { key_id, secret_key }) => {
+            let (token, _expires_at) = mint_paseto_token(key_id.as_deref(), secret_key, &host, "")?;
+            Ok(Some(format!("Bearer {}", token)))
+        }
+        _ => Ok(None),
+    }
+}
+
+// Credentials for digest auth are keyed by host, not by the full registry
+// reference scheme `DockerConfig` uses. A `BasicAuth` source rule takes
+// precedence, then a plain `Sources` HTTP auth entry, then a `BasicAuth`
+// entry in `DockerConfig.auths` for the same host.
+fn credentials_for_host(
+    url: &Url,
+    sources: Option<&Sources>,
+    docker_config: Option<&DockerConfig>,
+) -> Option<(String, String)> {
+    let host = host_and_port(url).ok()?;
+
+    let rule_auth = sources
+        .and_then(|sources| sources.rule_for_host(&host))
+        .and_then(|rule| rule.auth.as_ref());
+    if let Some(RegistryAuth::BasicAuth(username, password)) = rule_auth {
+        return Some((
+            String::from_utf8(username.clone()).ok()?,
+            String::from_utf8(password.clone()).ok()?,
+        ));
+    }
+
+    if let Some((username, password)) = sources.and_then(|sources| sources.http_auth(&host)) {
+        return Some((username.to_string(), password.to_string()));
+    }
+
+    match docker_config.and_then(|docker_config| docker_config.auths.get(&host)) {
+        Some(RegistryAuth::BasicAuth(username, password)) => Some((
+            String::from_utf8(username.clone()).ok()?,
+            String::from_utf8(password.clone()).ok()?,
+        )),
+        _ => None,
+    }
+}
+
+// An RFC 2617 Digest `WWW-Authenticate` challenge. `qop` is `None` for a
+// legacy (RFC 2069) challenge that omits the `qop` directive entirely, and
+// is then answered with the legacy `response` computation.
+struct DigestChallenge {
+    realm: String,
+    nonce: String,
+    qop: Option<String>,
+    opaque: Option<String>,
+}
+
+impl DigestChallenge {
+    fn parse(header: &str) -> Result<Self> {
+        let params = parse_challenge_params(
+            header
+                .trim_start()
+                .strip_prefix("Digest ")
+                .or_else(|| header.trim_start().strip_prefix("digest "))
+                .ok_or_else(|| anyhow!("not a Digest challenge: {}", header))?,
+        );
+
+        // We only implement the `auth` quality of protection, not `auth-int`
+        // (which additionally hashes the request body into HA2). A
+        // challenge offering only `auth-int` can't be answered correctly by
+        // pretending `auth` was negotiated, so reject it instead.
+        let qop = match params.get("qop") {
+            Some(offered) => {
+                if offered.split(',').map(str::trim).any(|qop| qop == "auth") {
+                    Some("auth".to_string())
+                } else {
+                    return Err(anyhow!(
+                        "Digest challenge only offers unsupported qop values: {}",
+                        offered
+                    ));
+                }
+            }
+            None => None,
+        };
+
+        Ok(DigestChallenge {
+            realm: params
+                .get("realm")
+                .cloned()
+                .ok_or_else(|| anyhow!("Digest challenge is missing realm"))?,
+            nonce: params
+                .get("nonce")
+                .cloned()
+                .ok_or_else(|| anyhow!("Digest challenge is missing nonce"))?,
+            qop,
+            opaque: params.get("opaque").cloned(),
+        })
+    }
+
+    // Builds the `Authorization: Digest ...` header value for a request,
+    // per https://datatracker.ietf.org/doc/html/rfc2617#section-3.2.2
+    // (or the legacy RFC 2069 form when the challenge carried no `qop`).
+    // `uri` must be the exact request-target (path + query) being sent.
+    fn authorization(&self, username: &str, password: &str, method: &str, uri: &str) -> String {
+        let ha1 = md5_hex(&format!("{}:{}:{}", username, self.realm, password));
+        let ha2 = md5_hex(&format!("{}:{}", method, uri));
+
+        let (response, qop_fields) = match &self.qop {
+            Some(qop) => {
+                let nc = "00000001";
+                let cnonce = random_hex(16);
+                let response = md5_hex(&format!(
+                    "{}:{}:{}:{}:{}:{}",
+                    ha1, self.nonce, nc, cnonce, qop, ha2
+                ));
+                (
+                    response,
+                    Some(format!(", qop={}, nc={}, cnonce=\"{}\"", qop, nc, cnonce)),
+                )
+            }
+            None => (md5_hex(&format!("{}:{}:{}", ha1, self.nonce, ha2)), None),
+        };
+
+        let mut authorization = format!(
+            "Digest username=\"{}\", realm=\"{}\", nonce=\"{}\", uri=\"{}\", response=\"{}\"",
+            username, self.realm, self.nonce, uri, response
+        );
+        if let Some(qop_fields) = qop_fields {
+            authorization.push_str(&qop_fields);
+        }
+        if let Some(opaque) = &self.opaque {
+            authorization.push_str(&format!(", opaque=\"{}\"", opaque));
+        }
+        authorization
+    }
+}
+
+// Splits on commas outside quoted strings, so a quoted list such as
+// `qop="auth,auth-int"` stays a single value.
+fn parse_challenge_params(params: &str) -> HashMap<String, String> {
+    let mut pairs = Vec::new();
+    let mut start = 0;
+    let mut in_quotes = false;
+    for (i, c) in params.char_indices() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            ',' if !in_quotes => {
+                pairs.push(&params[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    pairs.push(&params[start..]);
+
+    pairs
+        .into_iter()
+        .filter_map(|pair| {
+            let (key, value) = pair.split_once('=')?;
+            let value = value.trim().trim_matches('"');
+            Some((key.trim().to_string(), value.to_string()))
+        })
+        .collect()
+}
+
+fn md5_hex(data: &str) -> String {
+    format!("{:x}", md5::compute(data.as_bytes()))
+}
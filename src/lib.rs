@@ -4,6 +4,7 @@ extern crate rustls;
 extern crate walkdir;
 
 use anyhow::{anyhow, Result};
+use rand::RngCore;
 use std::boxed::Box;
 use url::Url;
 
@@ -69,6 +70,9 @@ pub async fn fetch_policy(
         }
         _ => unreachable!(),
     }
+    if let Some(sources) = sources {
+        sources.check_access(&host_and_port(&url)?, url.scheme())?;
+    }
     eprintln!("pulling policy...");
     url_fetcher(url.scheme())?
         .fetch(&url, &destination, sources, docker_config)
@@ -124,6 +128,14 @@ pub(crate) fn host_and_port(url: &Url) -> Result<String> {
     ))
 }
 
+// Shared by the PASETO and digest-auth code, both of which need a fresh
+// random value (a token nonce or a cnonce).
+pub(crate) fn random_hex(bytes_len: usize) -> String {
+    let mut bytes = vec![0u8; bytes_len];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
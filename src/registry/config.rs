@@ -1,13 +1,23 @@
+use crate::random_hex;
 use anyhow::{anyhow, Result};
 use oci_distribution::Reference;
-use serde::Deserialize;
+use pasetors::claims::Claims;
+use pasetors::keys::{AsymmetricPublicKey, AsymmetricSecretKey};
+use pasetors::version3::{PublicToken, V3};
+use serde::{Deserialize, Serialize};
 use std::io::Write;
 use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::{
     collections::HashMap, convert::TryFrom, convert::TryInto, fs::File, path::Path, str::FromStr,
 };
 use tracing::error;
 
+// PASETO asymmetric-key tokens are minted fresh for every pull, so they only
+// need to stay valid for the time it takes to complete that pull.
+const PASETO_TOKEN_TTL: Duration = Duration::from_secs(5 * 60);
+
 #[derive(Deserialize, Debug)]
 pub(crate) struct RegistryAuthRaw {
     // `auth` is optional because we have to be liberal on what we
@@ -16,6 +26,16 @@ pub(crate) struct RegistryAuthRaw {
     // semantically invalid. Check:
     // https://github.com/kubernetes/kubectl/issues/571
     auth: Option<String>,
+    // Present instead of `auth` when the registry issued a bearer/refresh
+    // token rather than a long-lived username/password pair.
+    identitytoken: Option<String>,
+    // Base64-encoded raw scalar bytes of a PASETO v3 (P-384) private key.
+    // When present, takes precedence over `auth`/`identitytoken`: a token
+    // is minted per pull instead of a long-lived secret being sent.
+    #[serde(rename = "secretKey")]
+    secret_key: Option<String>,
+    #[serde(rename = "keyId")]
+    key_id: Option<String>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -23,57 +43,300 @@ pub(crate) struct RegistryAuthRaw {
 pub struct DockerConfigRaw {
     auths: Option<HashMap<String, RegistryAuthRaw>>,
     creds_store: Option<String>,
+    // Per-registry credential helpers, keyed by registry host. Takes
+    // precedence over `creds_store` when a registry has an entry here.
+    cred_helpers: Option<HashMap<String, String>>,
+    // A generic credential command, not tied to the `docker-credential-`
+    // naming convention used by `creds_store`/`cred_helpers`. It is invoked
+    // with a JSON request on stdin and is expected to print a JSON
+    // credential object on stdout.
+    credential_process: Option<String>,
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum RegistryAuth {
     BasicAuth(Vec<u8>, Vec<u8>),
+    Token(String),
+    // A PASETO v3 (ECDSA P-384) private key used to mint a short-lived
+    // signed token for each pull, rather than sending a reusable secret.
+    // `secret_key` holds the raw scalar bytes of the private key.
+    AsymmetricKey {
+        key_id: Option<String>,
+        secret_key: Vec<u8>,
+    },
 }
 
 impl TryFrom<RegistryAuth> for sigstore::registry::Auth {
     type Error = anyhow::Error;
 
     fn try_from(ra: RegistryAuth) -> Result<Self> {
-        let RegistryAuth::BasicAuth(username, password) = ra;
-        Ok(sigstore::registry::Auth::Basic(
-            String::from_utf8(username).map_err(|e| anyhow!("username is not utf8: {:?}", e))?,
-            String::from_utf8(password).map_err(|e| anyhow!("password is not utf8: {:?}", e))?,
-        ))
+        match ra {
+            RegistryAuth::BasicAuth(username, password) => Ok(sigstore::registry::Auth::Basic(
+                String::from_utf8(username)
+                    .map_err(|e| anyhow!("username is not utf8: {:?}", e))?,
+                String::from_utf8(password)
+                    .map_err(|e| anyhow!("password is not utf8: {:?}", e))?,
+            )),
+            RegistryAuth::Token(token) => Ok(sigstore::registry::Auth::Bearer(token)),
+            RegistryAuth::AsymmetricKey { .. } => Err(anyhow!(
+                "AsymmetricKey credentials cannot be converted without a target registry and \
+                 repository to scope the minted token to; resolve them through \
+                 DockerConfig::auth instead"
+            )),
+        }
     }
 }
 
+// Signs a short-lived PASETO v3 `public` token for `registry`/`repository`,
+// keyed off the given private key, mirroring Cargo's RFC 3139 asymmetric
+// registry token work. The key id, if any, travels in the unencrypted
+// footer so the registry can select which registered public key to verify
+// against.
+pub(crate) fn mint_paseto_token(
+    key_id: Option<&str>,
+    secret_key: &[u8],
+    registry: &str,
+    repository: &str,
+) -> Result<(String, SystemTime)> {
+    let secret_key = AsymmetricSecretKey::<V3>::try_from(secret_key)
+        .map_err(|e| anyhow!("invalid PASETO v3 (P-384) secret key: {:?}", e))?;
+    let public_key = AsymmetricPublicKey::<V3>::try_from(&secret_key)
+        .map_err(|e| anyhow!("cannot derive PASETO v3 public key: {:?}", e))?;
+
+    let mut claims = Claims::new_expires_in(&PASETO_TOKEN_TTL)
+        .map_err(|e| anyhow!("cannot build PASETO claims: {:?}", e))?;
+    claims
+        .add_additional("registry", registry)
+        .map_err(|e| anyhow!("cannot set registry claim: {:?}", e))?;
+    claims
+        .add_additional("repository", repository)
+        .map_err(|e| anyhow!("cannot set repository claim: {:?}", e))?;
+    claims
+        .add_additional("nonce", random_hex(16))
+        .map_err(|e| anyhow!("cannot set nonce claim: {:?}", e))?;
+
+    let footer = paseto_footer(key_id)?;
+    let token = PublicToken::sign(&secret_key, &public_key, &claims, footer.as_deref(), None)
+        .map_err(|e| anyhow!("cannot sign PASETO token: {:?}", e))?;
+
+    Ok((token, SystemTime::now() + PASETO_TOKEN_TTL))
+}
+
+// Serializes the unencrypted `{"kid":"..."}` footer via serde_json rather
+// than string interpolation, so a `key_id` containing a `"` or `\` can't
+// produce a malformed or injected footer.
+fn paseto_footer(key_id: Option<&str>) -> Result<Option<Vec<u8>>> {
+    key_id
+        .map(|key_id| serde_json::to_vec(&serde_json::json!({ "kid": key_id })))
+        .transpose()
+        .map_err(|e| anyhow!("cannot serialize PASETO footer: {:?}", e))
+}
+
+// `AsymmetricKey` tokens are minted per registry/repository pair, so two
+// repositories on the same registry must not share a cache slot.
+fn asymmetric_key_cache_key(registry: &str, repository: &str) -> String {
+    format!("{}/{}", registry, repository)
+}
+
 #[derive(Deserialize)]
 #[serde(rename_all = "PascalCase")]
 struct CredentialsHelperResponse {
     username: String,
     secret: String,
+    identity_token: Option<String>,
+    // Unix timestamp (seconds) after which the credential should be
+    // considered stale and re-fetched from the helper.
+    expiration: Option<i64>,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Serialize)]
+struct CredentialProcessRequest<'a> {
+    operation: &'a str,
+    registry: &'a str,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CredentialProcessResponse {
+    username: String,
+    secret: String,
+    expiration: Option<i64>,
+}
+
+// A `RegistryAuth` resolved from a credentials helper or credential process,
+// cached until `expires_at` (if any) is reached.
+#[derive(Clone, Debug)]
+struct CachedCredential {
+    auth: RegistryAuth,
+    expires_at: Option<SystemTime>,
+}
+
+#[derive(Clone, Debug)]
 pub struct DockerConfig {
     pub auths: HashMap<String, RegistryAuth>,
     pub creds_store: Option<String>,
+    pub cred_helpers: HashMap<String, String>,
+    pub credential_process: Option<String>,
+    // Keyed by registry host (or by `registry/repository` for tokens minted
+    // from an `AsymmetricKey`, see `asymmetric_key_cache_key`), populated by
+    // `auth` so that repeated pulls
+    // against the same registry don't re-invoke the credentials helper or
+    // credential process for every policy fetched.
+    cache: Arc<Mutex<HashMap<String, CachedCredential>>>,
+}
+
+impl PartialEq for DockerConfig {
+    fn eq(&self, other: &Self) -> bool {
+        self.auths == other.auths
+            && self.creds_store == other.creds_store
+            && self.cred_helpers == other.cred_helpers
+            && self.credential_process == other.credential_process
+    }
 }
 
 impl DockerConfig {
+    // Resolves credentials for `registry`, preferring an exact per-host
+    // `cred_helpers` entry, then `creds_store`, then the inline `auths`
+    // entry for this registry, then the generic `credential_process`. Each
+    // source is a fallback for the next: a missing or failing helper/store
+    // doesn't abort resolution, it just falls through, so a global
+    // `creds_store`/`credential_process` can never dead-code a per-registry
+    // `auths` entry.
     pub fn auth(&self, image_url: &str) -> Result<Option<RegistryAuth>> {
         let reference =
             Reference::from_str(image_url.strip_prefix("registry://").unwrap_or(image_url))?;
+        let registry = reference.registry();
+
+        if let Some(cached) = self.cached_auth(registry) {
+            return Ok(Some(cached));
+        }
+
+        if let Some(auth) = self.auth_from_helper_or_store(registry) {
+            return Ok(Some(auth));
+        }
+
+        if let Some(auth) = self.auth_from_auths_entry(registry, &reference)? {
+            return Ok(Some(auth));
+        }
+
+        if let Some(credential_process) = &self.credential_process {
+            match get_auth_from_credential_process(credential_process, registry) {
+                Ok((auth, expires_at)) => {
+                    self.cache_auth(registry, auth.clone(), expires_at);
+                    return Ok(Some(auth));
+                }
+                Err(e) => error!(
+                    registry = %registry,
+                    error = %e,
+                    "credential process failed, no credentials resolved",
+                ),
+            }
+        }
+
+        Ok(None)
+    }
 
-        Ok(self.auths.get(reference.registry()).cloned())
+    // Tries the per-host `cred_helpers` entry, then the global `creds_store`,
+    // logging and falling through (rather than aborting) if the helper binary
+    // is missing or has no credentials for this registry.
+    fn auth_from_helper_or_store(&self, registry: &str) -> Option<RegistryAuth> {
+        let creds_store = self
+            .cred_helpers
+            .get(registry)
+            .or(self.creds_store.as_ref())?;
+        match self.cache_helper_auth(registry, creds_store, registry) {
+            Ok(auth) => Some(auth),
+            Err(e) => {
+                error!(
+                    registry = %registry,
+                    error = %e,
+                    "credentials helper failed, falling back to other credential sources",
+                );
+                None
+            }
+        }
+    }
+
+    // Looks up the inline `auths` entry for `registry`, minting a scoped
+    // PASETO token (and caching it separately, see `asymmetric_key_cache_key`)
+    // when it's an `AsymmetricKey`.
+    fn auth_from_auths_entry(
+        &self,
+        registry: &str,
+        reference: &Reference,
+    ) -> Result<Option<RegistryAuth>> {
+        match self.auths.get(registry).cloned() {
+            Some(RegistryAuth::AsymmetricKey { key_id, secret_key }) => {
+                // A minted token is scoped to both registry and repository, so
+                // it must not share a cache slot with another repository on the
+                // same registry.
+                let cache_key = asymmetric_key_cache_key(registry, reference.repository());
+                if let Some(cached) = self.cached_auth(&cache_key) {
+                    return Ok(Some(cached));
+                }
+                let (token, expires_at) = mint_paseto_token(
+                    key_id.as_deref(),
+                    &secret_key,
+                    registry,
+                    reference.repository(),
+                )?;
+                let auth = RegistryAuth::Token(token);
+                self.cache_auth(&cache_key, auth.clone(), Some(expires_at));
+                Ok(Some(auth))
+            }
+            other => Ok(other),
+        }
     }
 
     pub fn get_auth_from_credentials_helper_if_present(
         &self,
         registry: &str,
     ) -> Option<Result<RegistryAuth>> {
-        self.creds_store
-            .as_ref()
-            .map(|creds_store| get_auth_from_credentials_helper(creds_store.as_str(), registry))
+        if let Some(cached) = self.cached_auth(registry) {
+            return Some(Ok(cached));
+        }
+        self.cred_helpers
+            .get(registry)
+            .or(self.creds_store.as_ref())
+            .map(|creds_store| self.cache_helper_auth(registry, creds_store, registry))
+    }
+
+    fn cached_auth(&self, registry: &str) -> Option<RegistryAuth> {
+        let cache = self.cache.lock().expect("credential cache lock poisoned");
+        match cache.get(registry) {
+            Some(CachedCredential {
+                auth,
+                expires_at: None,
+            }) => Some(auth.clone()),
+            Some(CachedCredential {
+                auth,
+                expires_at: Some(expires_at),
+            }) if *expires_at > SystemTime::now() => Some(auth.clone()),
+            _ => None,
+        }
+    }
+
+    fn cache_auth(&self, registry: &str, auth: RegistryAuth, expires_at: Option<SystemTime>) {
+        let mut cache = self.cache.lock().expect("credential cache lock poisoned");
+        cache.insert(registry.to_string(), CachedCredential { auth, expires_at });
+    }
+
+    fn cache_helper_auth(
+        &self,
+        cache_key: &str,
+        creds_store: &str,
+        registry: &str,
+    ) -> Result<RegistryAuth> {
+        let (auth, expires_at) = get_auth_from_credentials_helper(creds_store, registry)?;
+        self.cache_auth(cache_key, auth.clone(), expires_at);
+        Ok(auth)
     }
 }
 
-fn get_auth_from_credentials_helper(creds_store: &str, registry: &str) -> Result<RegistryAuth> {
+fn get_auth_from_credentials_helper(
+    creds_store: &str,
+    registry: &str,
+) -> Result<(RegistryAuth, Option<SystemTime>)> {
     let mut process = Command::new(format!("docker-credential-{}", creds_store))
         .arg("get")
         .stdin(Stdio::piped())
@@ -93,12 +356,63 @@ fn get_auth_from_credentials_helper(creds_store: &str, registry: &str) -> Result
     }
 
     let response: CredentialsHelperResponse = serde_json::from_slice(res.stdout.as_slice())?;
-    Ok(RegistryAuth::BasicAuth(
-        response.username.into(),
-        response.secret.into(),
+    let expires_at = response.expiration.map(expiration_to_system_time);
+    if let Some(identity_token) = response.identity_token {
+        return Ok((RegistryAuth::Token(identity_token), expires_at));
+    }
+    Ok((
+        RegistryAuth::BasicAuth(response.username.into(), response.secret.into()),
+        expires_at,
+    ))
+}
+
+// Runs a user-configured credential command following a generic
+// credential-process protocol (modeled after Cargo's `credential-process`):
+// the command is handed a JSON request describing the operation and the
+// target registry on stdin, and is expected to print a JSON credential
+// object on stdout. Unlike `creds_store`/`cred_helpers`, the command name
+// is used verbatim and is not prefixed with `docker-credential-`.
+fn get_auth_from_credential_process(
+    command: &str,
+    registry: &str,
+) -> Result<(RegistryAuth, Option<SystemTime>)> {
+    let mut process = Command::new(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+    let stdin = process
+        .stdin
+        .as_mut()
+        .ok_or_else(|| anyhow!("Can't get stdin for credential process"))?;
+    let request = CredentialProcessRequest {
+        operation: "get",
+        registry,
+    };
+    stdin.write_all(serde_json::to_vec(&request)?.as_slice())?;
+    let res = process.wait_with_output()?;
+    if !res.status.success() {
+        return Err(anyhow!(
+            "Error retrieving credentials from credential process: {}",
+            String::from_utf8(res.stdout).unwrap_or_default()
+        ));
+    }
+
+    let response: CredentialProcessResponse = serde_json::from_slice(res.stdout.as_slice())?;
+    let expires_at = response.expiration.map(expiration_to_system_time);
+    Ok((
+        RegistryAuth::BasicAuth(response.username.into(), response.secret.into()),
+        expires_at,
     ))
 }
 
+fn expiration_to_system_time(expiration_unix_secs: i64) -> SystemTime {
+    if expiration_unix_secs.is_negative() {
+        UNIX_EPOCH
+    } else {
+        UNIX_EPOCH + Duration::from_secs(expiration_unix_secs as u64)
+    }
+}
+
 impl TryFrom<DockerConfigRaw> for DockerConfig {
     type Error = anyhow::Error;
 
@@ -123,6 +437,9 @@ impl TryFrom<DockerConfigRaw> for DockerConfig {
         Ok(DockerConfig {
             auths,
             creds_store: docker_config.creds_store,
+            cred_helpers: docker_config.cred_helpers.unwrap_or_default(),
+            credential_process: docker_config.credential_process,
+            cache: Arc::new(Mutex::new(HashMap::new())),
         })
     }
 }
@@ -133,6 +450,17 @@ impl TryFrom<RegistryAuthRaw> for OptionalRegistryAuth {
     type Error = anyhow::Error;
 
     fn try_from(auth: RegistryAuthRaw) -> Result<Self> {
+        if let Some(secret_key) = auth.secret_key {
+            let secret_key = base64::decode(secret_key)
+                .map_err(|_| anyhow!("invalid base64 encoding for secretKey"))?;
+            return Ok(Some(RegistryAuth::AsymmetricKey {
+                key_id: auth.key_id,
+                secret_key,
+            }));
+        }
+        if let Some(identity_token) = auth.identitytoken {
+            return Ok(Some(RegistryAuth::Token(identity_token)));
+        }
         if let Some(auth) = auth.auth {
             if let Ok(basic_auth) = base64::decode(auth) {
                 let splitted: Vec<&[u8]> = basic_auth.split(|c| *c == b':').collect();
@@ -172,17 +500,27 @@ mod tests {
                 RegistryAuthRaw {
                     // echo -n "username:password" | base64 -w0
                     auth: Some("dXNlcm5hbWU6cGFzc3dvcmQ=".to_string()),
+                    identitytoken: None,
+                    secret_key: None,
+                    key_id: None,
                 },
             ),
             (
                 "authless-registry.example.com".to_string(),
-                RegistryAuthRaw { auth: None },
+                RegistryAuthRaw {
+                    auth: None,
+                    identitytoken: None,
+                    secret_key: None,
+                    key_id: None,
+                },
             ),
         ];
 
         let docker_config: DockerConfig = DockerConfigRaw {
             auths: Some(HashMap::from_iter(auths)),
             creds_store: None,
+            cred_helpers: None,
+            credential_process: None,
         }
         .try_into()?;
 
@@ -190,4 +528,165 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_parses_cred_helpers_and_credential_process() -> Result<()> {
+        let raw: DockerConfigRaw = serde_json::from_str(
+            r#"{
+                "credsStore": "osxkeychain",
+                "credHelpers": {
+                    "123456789.dkr.ecr.us-east-1.amazonaws.com": "ecr-login"
+                },
+                "credentialProcess": "my-token-broker"
+            }"#,
+        )?;
+        let docker_config: DockerConfig = raw.try_into()?;
+
+        assert_eq!(docker_config.creds_store, Some("osxkeychain".to_string()));
+        assert_eq!(
+            docker_config
+                .cred_helpers
+                .get("123456789.dkr.ecr.us-east-1.amazonaws.com"),
+            Some(&"ecr-login".to_string())
+        );
+        assert_eq!(
+            docker_config.credential_process,
+            Some("my-token-broker".to_string())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parses_identity_token_from_auths() -> Result<()> {
+        let raw: DockerConfigRaw = serde_json::from_str(
+            r#"{
+                "auths": {
+                    "token-registry.example.com": {
+                        "auth": "",
+                        "identitytoken": "some-refresh-token"
+                    }
+                }
+            }"#,
+        )?;
+        let docker_config: DockerConfig = raw.try_into()?;
+
+        assert_eq!(
+            docker_config.auths.get("token-registry.example.com"),
+            Some(&RegistryAuth::Token("some-refresh-token".to_string()))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_cached_auth_is_returned_until_expired() -> Result<()> {
+        let docker_config: DockerConfig = DockerConfigRaw {
+            auths: None,
+            creds_store: None,
+            cred_helpers: None,
+            credential_process: None,
+        }
+        .try_into()?;
+        let auth = RegistryAuth::BasicAuth(b"user".to_vec(), b"pass".to_vec());
+
+        docker_config.cache_auth(
+            "registry.example.com",
+            auth.clone(),
+            Some(SystemTime::now() + Duration::from_secs(60)),
+        );
+        assert_eq!(
+            docker_config.cached_auth("registry.example.com"),
+            Some(auth.clone())
+        );
+
+        docker_config.cache_auth(
+            "expired.example.com",
+            auth,
+            Some(SystemTime::now() - Duration::from_secs(60)),
+        );
+        assert_eq!(docker_config.cached_auth("expired.example.com"), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_asymmetric_key_cache_is_scoped_per_repository() -> Result<()> {
+        let docker_config: DockerConfig = DockerConfigRaw {
+            auths: None,
+            creds_store: None,
+            cred_helpers: None,
+            credential_process: None,
+        }
+        .try_into()?;
+        let token_for_first_repo = RegistryAuth::Token("first-repo-token".to_string());
+
+        docker_config.cache_auth(
+            &asymmetric_key_cache_key("registry.example.com", "repo-one"),
+            token_for_first_repo.clone(),
+            Some(SystemTime::now() + Duration::from_secs(60)),
+        );
+
+        assert_eq!(
+            docker_config.cached_auth(&asymmetric_key_cache_key("registry.example.com", "repo-one")),
+            Some(token_for_first_repo)
+        );
+        assert_eq!(
+            docker_config.cached_auth(&asymmetric_key_cache_key("registry.example.com", "repo-two")),
+            None
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_asymmetric_key_cannot_convert_without_a_registry() {
+        // `TryFrom<RegistryAuth>` has no registry/repository to scope a
+        // freshly-minted token to, so it must never sign one itself;
+        // callers are expected to go through `DockerConfig::auth` instead.
+        let auth = RegistryAuth::AsymmetricKey {
+            key_id: Some("key-1".to_string()),
+            secret_key: vec![0u8; 4],
+        };
+
+        assert!(sigstore::registry::Auth::try_from(auth).is_err());
+    }
+
+    #[test]
+    fn test_paseto_footer_escapes_key_id() -> Result<()> {
+        let footer = paseto_footer(Some(r#"key-1", "pwned": "yes"#))
+            .unwrap()
+            .unwrap();
+
+        let parsed: serde_json::Value = serde_json::from_slice(&footer)?;
+        assert_eq!(parsed["kid"], r#"key-1", "pwned": "yes"#);
+        assert!(parsed.get("pwned").is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parses_secret_key_from_auths() -> Result<()> {
+        let raw: DockerConfigRaw = serde_json::from_str(
+            r#"{
+                "auths": {
+                    "asymmetric-registry.example.com": {
+                        "secretKey": "AAECAw==",
+                        "keyId": "key-1"
+                    }
+                }
+            }"#,
+        )?;
+        let docker_config: DockerConfig = raw.try_into()?;
+
+        assert_eq!(
+            docker_config.auths.get("asymmetric-registry.example.com"),
+            Some(&RegistryAuth::AsymmetricKey {
+                key_id: Some("key-1".to_string()),
+                secret_key: vec![0, 1, 2, 3],
+            })
+        );
+
+        Ok(())
+    }
 }
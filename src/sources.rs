@@ -0,0 +1,208 @@
+use crate::registry::config::RegistryAuth;
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+/// Describes where policies may be fetched from and how to authenticate to
+/// them. Besides per-host Basic/Digest credentials for plain HTTP(S) policy
+/// URLs (registry credentials otherwise live in `DockerConfig`), it carries
+/// a set of host-matched rules that gate which registries/hosts policies may
+/// be pulled from and how.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Sources {
+    http_auths: HashMap<String, (String, String)>,
+    rules: Vec<SourceRule>,
+}
+
+impl Sources {
+    /// Returns the username/password configured for `host`, if any.
+    pub fn http_auth(&self, host: &str) -> Option<(&str, &str)> {
+        self.http_auths
+            .get(host)
+            .map(|(username, password)| (username.as_str(), password.as_str()))
+    }
+
+    pub fn add_rule(&mut self, rule: SourceRule) {
+        self.rules.push(rule);
+    }
+
+    /// Returns the most specific rule matching `host`, preferring an exact
+    /// host match over a `*.`-suffix match.
+    pub fn rule_for_host(&self, host: &str) -> Option<&SourceRule> {
+        self.rules
+            .iter()
+            .filter(|rule| rule.host.matches(host))
+            .max_by_key(|rule| rule.host.specificity())
+    }
+
+    /// Fails closed: errors if `host` is denied by a matching rule, or if
+    /// `scheme` is plain HTTP and the matching rule doesn't allow insecure
+    /// access. A host with no matching rule is allowed.
+    pub fn check_access(&self, host: &str, scheme: &str) -> Result<()> {
+        let rule = match self.rule_for_host(host) {
+            Some(rule) => rule,
+            None => return Ok(()),
+        };
+
+        if !rule.allow {
+            return Err(anyhow!("access to {} is denied by source rules", host));
+        }
+        if scheme == "http" && !rule.insecure_allowed {
+            return Err(anyhow!(
+                "{} requires HTTPS, but source rules for this host do not allow insecure access",
+                host
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// A rule matching a registry/host, optionally using a `*.` prefix to match
+/// any subdomain (e.g. `*.corp.example.com`).
+#[derive(Clone, Debug, PartialEq)]
+pub struct SourceRule {
+    host: HostPattern,
+    allow: bool,
+    pub insecure_allowed: bool,
+    pub ca_file: Option<PathBuf>,
+    pub auth: Option<RegistryAuth>,
+}
+
+impl SourceRule {
+    pub fn allow(host_pattern: &str) -> Self {
+        SourceRule {
+            host: HostPattern::parse(host_pattern),
+            allow: true,
+            insecure_allowed: false,
+            ca_file: None,
+            auth: None,
+        }
+    }
+
+    pub fn deny(host_pattern: &str) -> Self {
+        SourceRule {
+            host: HostPattern::parse(host_pattern),
+            allow: false,
+            insecure_allowed: false,
+            ca_file: None,
+            auth: None,
+        }
+    }
+
+    /// Permits plain HTTP (for `http://` policy URLs) or connecting over an
+    /// otherwise-untrusted TLS certificate. Only the `Https` fetcher honors
+    /// this (and `ca_file`/`auth`) today; the `registry://` fetcher in this
+    /// tree does not yet consult `Sources` rules at all.
+    pub fn insecure_allowed(mut self, insecure_allowed: bool) -> Self {
+        self.insecure_allowed = insecure_allowed;
+        self
+    }
+
+    pub fn ca_file(mut self, ca_file: PathBuf) -> Self {
+        self.ca_file = Some(ca_file);
+        self
+    }
+
+    pub fn auth(mut self, auth: RegistryAuth) -> Self {
+        self.auth = Some(auth);
+        self
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum HostPattern {
+    Exact(String),
+    WildcardSuffix(String),
+}
+
+impl HostPattern {
+    fn parse(pattern: &str) -> Self {
+        match pattern.strip_prefix("*.") {
+            Some(suffix) => HostPattern::WildcardSuffix(suffix.to_string()),
+            None => HostPattern::Exact(pattern.to_string()),
+        }
+    }
+
+    fn matches(&self, host: &str) -> bool {
+        match self {
+            HostPattern::Exact(exact) => exact == host,
+            HostPattern::WildcardSuffix(suffix) => host
+                .strip_suffix(suffix.as_str())
+                .map(|prefix| prefix.ends_with('.'))
+                .unwrap_or(false),
+        }
+    }
+
+    // Exact matches are always more specific than a wildcard; among
+    // wildcards, the longer suffix is the more specific match.
+    fn specificity(&self) -> usize {
+        match self {
+            HostPattern::Exact(exact) => exact.len() + 1,
+            HostPattern::WildcardSuffix(suffix) => suffix.len(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exact_rule_matches_only_that_host() {
+        let mut sources = Sources::default();
+        sources.add_rule(SourceRule::deny("evil.example.com"));
+
+        assert!(sources.rule_for_host("evil.example.com").is_some());
+        assert!(sources.rule_for_host("good.example.com").is_none());
+    }
+
+    #[test]
+    fn test_wildcard_rule_matches_subdomains_not_root() {
+        let mut sources = Sources::default();
+        sources.add_rule(SourceRule::allow("*.corp.example.com"));
+
+        assert!(sources.rule_for_host("registry.corp.example.com").is_some());
+        assert!(sources.rule_for_host("corp.example.com").is_none());
+        assert!(sources.rule_for_host("other.com").is_none());
+    }
+
+    #[test]
+    fn test_exact_rule_takes_precedence_over_wildcard() {
+        let mut sources = Sources::default();
+        sources.add_rule(SourceRule::allow("*.corp.example.com").insecure_allowed(true));
+        sources.add_rule(SourceRule::deny("registry.corp.example.com"));
+
+        let rule = sources.rule_for_host("registry.corp.example.com").unwrap();
+        assert!(!rule.allow);
+    }
+
+    #[test]
+    fn test_check_access_denies_matching_rule() {
+        let mut sources = Sources::default();
+        sources.add_rule(SourceRule::deny("evil.example.com"));
+
+        assert!(sources.check_access("evil.example.com", "https").is_err());
+        assert!(sources.check_access("other.example.com", "https").is_ok());
+    }
+
+    #[test]
+    fn test_check_access_fails_closed_on_insecure_scheme() {
+        let mut sources = Sources::default();
+        sources.add_rule(SourceRule::allow("registry.example.com"));
+
+        assert!(sources
+            .check_access("registry.example.com", "http")
+            .is_err());
+    }
+
+    #[test]
+    fn test_check_access_allows_insecure_scheme_when_permitted() {
+        let mut sources = Sources::default();
+        sources.add_rule(SourceRule::allow("registry.example.com").insecure_allowed(true));
+
+        assert!(sources
+            .check_access("registry.example.com", "http")
+            .is_ok());
+    }
+}